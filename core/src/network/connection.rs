@@ -0,0 +1,79 @@
+//! A [`ConnectionIOManager`] wrapped in a compile-time protocol state,
+//! so a connection that hasn't finished logging in simply has no
+//! `into_play()` to call.
+
+use super::packet::{Packet, PacketDirection};
+use super::serialize::{CodecError, ConnectionIOManager};
+use super::state::{Handshake, Login, Play, ProtocolState, Status};
+use bytes::BytesMut;
+use std::marker::PhantomData;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// A connection typed by its position in the protocol state machine
+/// (see [`ProtocolState`]) - there's no way to hold a
+/// `Connection<Play>` that skipped `Login`.
+pub struct Connection<St: ProtocolState> {
+    manager: ConnectionIOManager,
+    _state: PhantomData<St>,
+}
+
+impl Connection<Handshake> {
+    pub fn new(direction: PacketDirection) -> Self {
+        Connection {
+            manager: ConnectionIOManager::new(direction),
+            _state: PhantomData,
+        }
+    }
+
+    /// Taken once the client's `Handshake` declares it wants to query
+    /// the server list.
+    pub fn into_status(self) -> Connection<Status> {
+        self.into_state()
+    }
+
+    /// Taken once the client's `Handshake` declares it wants to log in.
+    pub fn into_login(self) -> Connection<Login> {
+        self.into_state()
+    }
+}
+
+impl Connection<Login> {
+    /// Taken once login completes (`LoginSuccess` has been sent).
+    pub fn into_play(self) -> Connection<Play> {
+        self.into_state()
+    }
+}
+
+impl<St: ProtocolState> Connection<St> {
+    fn into_state<St2: ProtocolState>(self) -> Connection<St2> {
+        Connection {
+            manager: self.manager,
+            _state: PhantomData,
+        }
+    }
+
+    pub fn manager(&self) -> &ConnectionIOManager {
+        &self.manager
+    }
+
+    pub fn manager_mut(&mut self) -> &mut ConnectionIOManager {
+        &mut self.manager
+    }
+}
+
+impl<St: ProtocolState> Decoder for Connection<St> {
+    type Item = Box<dyn Packet>;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.manager.decode_packet(src, St::STAGE)
+    }
+}
+
+impl<St: ProtocolState> Encoder<Box<dyn Packet>> for Connection<St> {
+    type Error = CodecError;
+
+    fn encode(&mut self, packet: Box<dyn Packet>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.manager.encode(packet, dst)
+    }
+}
@@ -3,7 +3,7 @@ use super::packet::{Packet, PacketDirection, PacketId, PacketStage, PacketType};
 use crate::bytebuf::ByteBuf;
 use crate::prelude::*;
 use aes::Aes128;
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BytesMut};
 use cfb8::stream_cipher::{NewStreamCipher, StreamCipher};
 use cfb8::Cfb8;
 use flate2::{
@@ -12,25 +12,326 @@ use flate2::{
 };
 use std::io::prelude::*;
 use std::io::Cursor;
+use std::time::{Duration, Instant};
+use tokio_util::codec::Encoder;
 
 type AesCfb8 = Cfb8<Aes128>;
 
+/// Largest value representable by the protocol's 3-byte VarInt length
+/// prefix, so also the largest frame (or declared uncompressed size)
+/// a client is allowed to send.
+const MAX_PACKET_SIZE: usize = 2_097_151;
+
+/// How long a client may leave a frame half-sent before it's treated
+/// as a slow-loris attempt and disconnected.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The two raw bytes a pre-1.7 client (or a legacy server-list pinger)
+/// sends instead of a VarInt-framed `Handshake` packet.
+const LEGACY_PING_PREFIX: [u8; 2] = [0xFE, 0x01];
+
+/// Synthetic packet id `LegacyPingFormat` hands back so the usual
+/// `PacketType::get_from_id` dispatch can route it under
+/// `PacketStage::Handshake` like any other packet.
+const LEGACY_PING_PACKET_ID: i32 = 0xFE;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(std::io::Error),
+    InvalidPacket,
+    PacketTooLarge,
+    ReceiveTimeout,
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "{}", e),
+            CodecError::InvalidPacket => write!(f, "peer sent an invalid packet"),
+            CodecError::PacketTooLarge => write!(f, "peer sent a packet exceeding the size limit"),
+            CodecError::ReceiveTimeout => write!(f, "peer took too long to finish sending a packet"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<std::io::Error> for CodecError {
+    fn from(e: std::io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+impl From<()> for CodecError {
+    fn from(_: ()) -> Self {
+        CodecError::InvalidPacket
+    }
+}
+
+impl From<FormatError> for CodecError {
+    fn from(e: FormatError) -> Self {
+        match e {
+            FormatError::Invalid => CodecError::InvalidPacket,
+            FormatError::TooLarge => CodecError::PacketTooLarge,
+        }
+    }
+}
+
+/// Error from [`PacketFormat::receive`] - kept distinct from a plain
+/// `()` so `CodecError` can still tell a too-large frame apart from a
+/// malformed one.
+#[derive(Debug)]
+pub enum FormatError {
+    Invalid,
+    TooLarge,
+}
+
+impl From<()> for FormatError {
+    fn from(_: ()) -> Self {
+        FormatError::Invalid
+    }
+}
+
+/// Byte-level framing for a connection - length prefixing and (if
+/// negotiated) compression. `ConnectionIOManager` holds one behind a
+/// `Box` so it can be swapped, e.g. for `LegacyPingFormat` on a
+/// pre-1.7 client.
+pub trait PacketFormat: Send {
+    /// Appends the framed form of `body` to `out`.
+    fn send(&mut self, out: &mut BytesMut, body: &[u8]);
+
+    /// Splits one complete packet body off the front of `buf` in
+    /// place. `Ok(None)` means `buf` doesn't hold a full frame yet and
+    /// is left untouched; `Err` means it can't possibly become one and
+    /// the connection should be dropped.
+    fn receive(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, FormatError>;
+}
+
+/// The modern (1.7+) VarInt length-prefixed framing, with optional
+/// zlib compression of bodies at or above `compression_threshold`.
+pub struct DefaultPacketFormat {
+    compression_threshold: Option<usize>,
+    max_packet_size: usize,
+}
+
+impl DefaultPacketFormat {
+    pub fn new(max_packet_size: usize) -> Self {
+        Self {
+            compression_threshold: None,
+            max_packet_size,
+        }
+    }
+
+    pub fn with_compression(threshold: usize, max_packet_size: usize) -> Self {
+        Self {
+            compression_threshold: Some(threshold),
+            max_packet_size,
+        }
+    }
+}
+
+impl PacketFormat for DefaultPacketFormat {
+    fn send(&mut self, out: &mut BytesMut, body: &[u8]) {
+        // Reserve a fixed 3-byte length placeholder, write the body,
+        // then patch the placeholder in place now the length's known.
+        let frame_start = out.len();
+        out.extend_from_slice(&[0, 0, 0]);
+
+        match self.compression_threshold {
+            Some(threshold) if body.len() >= threshold => {
+                put_var_int(out, body.len() as i32);
+                compress_data(body, out);
+            }
+            Some(_) => {
+                put_var_int(out, 0);
+                out.extend_from_slice(body);
+            }
+            None => {
+                out.extend_from_slice(body);
+            }
+        }
+
+        let frame_len = out.len() - frame_start - 3;
+        write_var_int_3(&mut out[frame_start..frame_start + 3], frame_len);
+    }
+
+    fn receive(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, FormatError> {
+        let (length, length_size) = match peek_var_int(buf)? {
+            Some(val) => val,
+            None => return Ok(None),
+        };
+        let length = length as usize;
+
+        if length > self.max_packet_size {
+            warn!(
+                "Client sent a frame of {} bytes, exceeding the {}-byte limit",
+                length, self.max_packet_size
+            );
+            return Err(FormatError::TooLarge);
+        }
+
+        if buf.len() < length_size + length {
+            return Ok(None);
+        }
+
+        buf.advance(length_size);
+        let mut frame = buf.split_to(length);
+
+        if self.compression_threshold.is_some() {
+            let (uncompressed_size, prefix_size) = match peek_var_int(&frame)? {
+                Some(val) => val,
+                None => return Err(FormatError::Invalid),
+            };
+            if uncompressed_size as usize > self.max_packet_size {
+                warn!(
+                    "Client sent a packet declaring an uncompressed size of {} bytes, exceeding the {}-byte limit",
+                    uncompressed_size, self.max_packet_size
+                );
+                return Err(FormatError::TooLarge);
+            }
+            frame.advance(prefix_size);
+
+            if uncompressed_size == 0 {
+                Ok(Some(frame))
+            } else {
+                let mut decompressed = BytesMut::with_capacity(uncompressed_size as usize);
+                decompress_data(&frame, uncompressed_size, &mut decompressed, self.max_packet_size)?;
+                Ok(Some(decompressed))
+            }
+        } else {
+            Ok(Some(frame))
+        }
+    }
+}
+
+/// Recognizes the legacy (pre-1.7) server list ping, `0xFE 0x01`, so
+/// old clients and pingers get a response instead of being
+/// disconnected as "invalid id".
+pub struct LegacyPingFormat;
+
+impl PacketFormat for LegacyPingFormat {
+    fn send(&mut self, out: &mut BytesMut, body: &[u8]) {
+        out.extend_from_slice(body);
+    }
+
+    fn receive(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, FormatError> {
+        if buf.len() < LEGACY_PING_PREFIX.len() {
+            return Ok(None);
+        }
+
+        if buf[..LEGACY_PING_PREFIX.len()] != LEGACY_PING_PREFIX {
+            return Err(FormatError::Invalid);
+        }
+
+        // Nothing past the prefix is meaningful here, but it's part of
+        // the same write a real client sends - consume all of it, or
+        // the next `decode` call sees it and rejects the connection.
+        buf.advance(buf.len());
+
+        let mut result = BytesMut::with_capacity(1);
+        put_var_int(&mut result, LEGACY_PING_PACKET_ID);
+        Ok(Some(result))
+    }
+}
+
+/// Writes `value` as a normal (minimally-sized) VarInt to the end of
+/// `out`.
+fn put_var_int(out: &mut BytesMut, value: i32) {
+    let mut value = value as u32;
+    loop {
+        let mut byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+        }
+        out.extend_from_slice(&[byte]);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+/// Writes `value` into the 3 bytes of `out` as a VarInt padded to
+/// exactly that width, using the continuation bit even where the
+/// minimal encoding would be shorter - `out` is a fixed-size
+/// placeholder reserved before the frame body was written.
+fn write_var_int_3(out: &mut [u8], value: usize) {
+    assert!(value <= MAX_PACKET_SIZE, "frame exceeds MAX_PACKET_SIZE");
+    out[0] = ((value & 0x7F) as u8) | 0x80;
+    out[1] = (((value >> 7) & 0x7F) as u8) | 0x80;
+    out[2] = ((value >> 14) & 0x7F) as u8;
+}
+
+/// Reads from `coder` until EOF rather than assuming one `read()` call
+/// returns the whole (de)compressed body, appending to `output` as it
+/// goes. Bails out with `FormatError::TooLarge` the moment `output`
+/// would grow past `limit` bytes - checked against the bytes actually
+/// produced, not just whatever size the sender claimed - so a zlib
+/// bomb is caught mid-stream instead of being decompressed in full
+/// first. Also surfaces a decompression error as `FormatError::Invalid`
+/// instead of panicking the connection's task.
+fn read_to_end<R: Read>(mut coder: R, output: &mut BytesMut, limit: usize) -> Result<(), FormatError> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        let amt = coder.read(&mut chunk).map_err(|_| FormatError::Invalid)?;
+        if amt == 0 {
+            break;
+        }
+        if output.len() + amt > limit {
+            return Err(FormatError::TooLarge);
+        }
+        output.extend_from_slice(&chunk[..amt]);
+    }
+    Ok(())
+}
+
+fn compress_data(data: &[u8], output: &mut BytesMut) {
+    read_to_end(ZlibEncoder::new(data, Compression::default()), output, usize::MAX)
+        .expect("compressing our own outgoing data should never fail");
+}
+
+fn decompress_data(
+    data: &[u8],
+    uncompressed_size: i32,
+    output: &mut BytesMut,
+    limit: usize,
+) -> Result<(), FormatError> {
+    output.reserve((uncompressed_size.max(0) as usize).min(limit));
+    read_to_end(ZlibDecoder::new(data), output, limit)
+}
+
 pub struct ConnectionIOManager {
     encryption_enabled: bool,
     encryption_key: [u8; 16],
-    compression_enabled: bool,
-    compression_threshold: usize,
 
-    pending_received_packets: Option<Vec<Box<dyn Packet>>>,
+    /// Remembered so `set_max_packet_size` can rebuild `format` with
+    /// the same compression setting it already had.
+    compression_threshold: Option<usize>,
+
+    format: Box<dyn PacketFormat>,
 
-    incoming_compressed: ByteBuf,
     incoming_uncompressed: ByteBuf,
 
+    /// Leading bytes of the `decode` source buffer already run through
+    /// `decrypt_data` - only newly-arrived bytes may be decrypted, or
+    /// the CFB8 keystream desyncs.
+    decrypted_len: usize,
+
+    /// Largest frame (or declared uncompressed size) this manager will
+    /// accept. Defaults to `MAX_PACKET_SIZE`.
+    max_packet_size: usize,
+
+    /// Set once `decode` has seen the start of a frame but not yet
+    /// the rest of it, cleared once the frame completes.
+    partial_frame_since: Option<Instant>,
+
+    /// Whether this connection's opening bytes have been checked for
+    /// a legacy ping yet. Only ever checked once.
+    legacy_ping_checked: bool,
+
     encrypter: Option<AesCfb8>,
     decrypter: Option<AesCfb8>,
 
-    stage: PacketStage,
-
     direction: PacketDirection,
 }
 
@@ -39,26 +340,23 @@ impl ConnectionIOManager {
         Self {
             encryption_enabled: false,
             encryption_key: [0; 16],
-            compression_enabled: false,
-            compression_threshold: 0,
-            pending_received_packets: Some(vec![]),
 
-            incoming_compressed: ByteBuf::with_capacity(128),
+            compression_threshold: None,
+            format: Box::new(DefaultPacketFormat::new(MAX_PACKET_SIZE)),
+
             incoming_uncompressed: ByteBuf::with_capacity(128),
+            decrypted_len: 0,
+            max_packet_size: MAX_PACKET_SIZE,
+            partial_frame_since: None,
+            legacy_ping_checked: false,
 
             encrypter: None,
             decrypter: None,
 
-            stage: PacketStage::Handshake,
-
             direction,
         }
     }
 
-    pub fn set_stage(&mut self, stage: PacketStage) {
-        self.stage = stage;
-    }
-
     pub fn enable_encryption(&mut self, key: [u8; 16]) {
         self.encryption_enabled = true;
         self.encryption_key = key;
@@ -70,203 +368,252 @@ impl ConnectionIOManager {
     }
 
     pub fn enable_compression(&mut self, threshold: usize) {
-        self.compression_enabled = true;
-        self.compression_threshold = threshold;
+        self.compression_threshold = Some(threshold);
+        self.format = Box::new(DefaultPacketFormat::with_compression(
+            threshold,
+            self.max_packet_size,
+        ));
 
         trace!("Enabling compression");
     }
 
-    /// `Err` is returned only if something happens that indicates
-    /// a malicious client. If `Err` is returned, the client should
-    /// be disconnected immediately.
-    pub fn accept_data(&mut self, mut data: ByteBuf) -> Result<(), ()> {
-        // Decrypt if needed
-        if self.encryption_enabled {
-            self.decrypt_data(data.bytes_from_start());
+    /// Overrides the maximum accepted frame/uncompressed-body size.
+    /// Defaults to `MAX_PACKET_SIZE`, the protocol's own cap.
+    pub fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.max_packet_size = max_packet_size;
+        self.format = match self.compression_threshold {
+            Some(threshold) => Box::new(DefaultPacketFormat::with_compression(
+                threshold,
+                max_packet_size,
+            )),
+            None => Box::new(DefaultPacketFormat::new(max_packet_size)),
+        };
+    }
+
+    /// Whether the client has left a frame partially sent for longer
+    /// than `RECEIVE_TIMEOUT`. `decode_packet` checks this itself, but
+    /// that alone only catches it the next time bytes arrive - a
+    /// client that stops sending entirely goes quiet with no more
+    /// `decode` calls to notice, so the owning connection task should
+    /// also poll this directly (e.g. on a `tokio::time::interval`) and
+    /// disconnect the client if it returns `true`.
+    pub fn receive_timed_out(&self) -> bool {
+        match self.partial_frame_since {
+            Some(since) => since.elapsed() > RECEIVE_TIMEOUT,
+            None => false,
         }
+    }
 
-        self.incoming_compressed.write_all(data.inner()).unwrap();
+    fn encrypt_data(&mut self, data: &mut [u8]) {
+        let crypter = self.encrypter.as_mut().unwrap();
+        crypter.encrypt(data);
+    }
+
+    fn decrypt_data(&mut self, data: &mut [u8]) {
+        let crypter = self.decrypter.as_mut().unwrap();
+        crypter.decrypt(data);
+    }
+}
+
+/// Peeks a VarInt from the front of `buf` without consuming any
+/// bytes, returning the decoded value together with the number of
+/// bytes it occupies. Returns `Ok(None)` if `buf` does not yet hold a
+/// complete VarInt, since more bytes may still arrive.
+fn peek_var_int(buf: &[u8]) -> Result<Option<(i32, usize)>, ()> {
+    let mut value: i32 = 0;
+    for i in 0..5 {
+        let byte = match buf.get(i) {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+
+        value |= ((byte & 0x7F) as i32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
 
-        loop {
-            let pending_buf = &mut self.incoming_compressed;
+    Err(())
+}
 
-            // Mark reader index so we can return to this
-            // position in the buffer if the packet is incomplete
-            pending_buf.mark_read_position();
+impl ConnectionIOManager {
+    /// The `Decoder` logic for [`Connection<St>`](super::connection::Connection),
+    /// which supplies `stage` as `St::STAGE`.
+    ///
+    /// `Err` is returned only if something happens that indicates
+    /// a malicious client. If `Err` is returned, the client should
+    /// be disconnected immediately.
+    pub(crate) fn decode_packet(
+        &mut self,
+        src: &mut BytesMut,
+        stage: PacketStage,
+    ) -> Result<Option<Box<dyn Packet>>, CodecError> {
+        if self.receive_timed_out() {
+            return Err(CodecError::ReceiveTimeout);
+        }
 
-            let mut packet_length = {
-                if let Ok(val) = pending_buf.read_var_int() {
-                    val
-                } else {
-                    pending_buf.reset_read_position();
-                    break;
-                }
-            };
+        // Decrypt only the tail that has arrived since the last call.
+        if self.encryption_enabled && src.len() > self.decrypted_len {
+            let start = self.decrypted_len;
+            self.decrypt_data(&mut src[start..]);
+            self.decrypted_len = src.len();
+        }
 
-            // Check that the entire packet is received - otherwise, return and
-            // wait for more bytes
-            if (pending_buf.remaining() as i32) < packet_length {
-                pending_buf.reset_read_position();
-                return Ok(());
+        // Only latches once enough bytes have actually arrived to
+        // tell either way - the prefix can arrive split across reads,
+        // and checking (then giving up) on a single leading byte would
+        // miss a legacy ping that completes on the next call.
+        if !self.legacy_ping_checked && src.len() >= LEGACY_PING_PREFIX.len() {
+            self.legacy_ping_checked = true;
+            if stage == PacketStage::Handshake
+                && src[..LEGACY_PING_PREFIX.len()] == LEGACY_PING_PREFIX
+            {
+                self.format = Box::new(LegacyPingFormat);
             }
+        }
 
-            pending_buf.mark_read_position();
-
-            // If compression is enabled, read the uncompressed length
-            // and decompress - otherwise, copy bytes to incoming_uncompressed
-            let len_of_compressed_size_field;
-            if self.compression_enabled {
-                let uncompressed_size = pending_buf.read_var_int()?;
-                if uncompressed_size != 0 {
-                    packet_length = uncompressed_size;
-                    self.decompress_data(uncompressed_size);
-                    len_of_compressed_size_field = 0;
-                } else {
-                    self.incoming_uncompressed
-                        .write_all(&pending_buf.inner()[..(packet_length - 1) as usize])
-                        .unwrap();
-                    len_of_compressed_size_field =
-                        pending_buf.read_pos() - pending_buf.marked_read_position();
-                    self.incoming_compressed
-                        .advance((packet_length - 1) as usize);
+        let before = src.len();
+        let body = match self.format.receive(src) {
+            Ok(Some(body)) => body,
+            Ok(None) => {
+                if self.partial_frame_since.is_none() && !src.is_empty() {
+                    self.partial_frame_since = Some(Instant::now());
                 }
-            } else {
-                len_of_compressed_size_field = 0;
-                let buf = &pending_buf.inner()[..(packet_length as usize)];
-                self.incoming_uncompressed.write_all(buf).unwrap();
-                self.incoming_compressed.advance(packet_length as usize);
+                return Ok(None);
             }
+            Err(e) => return Err(e.into()),
+        };
+        self.partial_frame_since = None;
 
-            self.incoming_compressed.remove_prior();
-
-            let buf = &mut self.incoming_uncompressed;
-            buf.mark_read_position();
-
-            let packet_id = buf.read_var_int()?;
-            let stage = self.stage;
+        if self.encryption_enabled {
+            self.decrypted_len -= before - src.len();
+        }
 
-            let packet_type =
-                PacketType::get_from_id(PacketId(packet_id as u32, self.direction, stage));
-            if packet_type.is_err() {
-                warn!(
-                    "Client sent packet with invalid id {} for stage {:?}",
-                    packet_id, stage
-                );
+        self.incoming_uncompressed.clear();
+        self.incoming_uncompressed.write_all(&body).unwrap();
 
-                return Err(());
-            }
+        let buf = &mut self.incoming_uncompressed;
+        buf.mark_read_position();
+        let packet_id = buf.read_var_int()?;
 
-            trace!("Received packet with type {:?}", packet_type.unwrap());
+        let packet_type =
+            PacketType::get_from_id(PacketId(packet_id as u32, self.direction, stage));
+        if packet_type.is_err() {
+            warn!(
+                "Client sent packet with invalid id {} for stage {:?}",
+                packet_id, stage
+            );
 
-            let mut packet = packet_type.unwrap().get_implementation();
-            let upper_index = packet_length as usize
-                - (buf.read_pos() - buf.marked_read_position())
-                - len_of_compressed_size_field;
-            {
-                let mut slice = Cursor::new(&buf.inner()[..upper_index]);
-                packet.read_from(&mut slice)?;
-            }
-            buf.advance(upper_index);
-
-            if packet.ty() == PacketType::Handshake {
-                let handshake =
-                    cast_packet::<crate::network::packet::implementation::Handshake>(&*packet);
-                match handshake.next_state {
-                    crate::network::packet::implementation::HandshakeState::Login => {
-                        self.stage = PacketStage::Login
-                    }
-                    crate::network::packet::implementation::HandshakeState::Status => {
-                        self.stage = PacketStage::Status
-                    }
-                }
-            }
+            return Err(CodecError::InvalidPacket);
+        }
+        let packet_type = packet_type.unwrap();
 
-            buf.remove_prior();
+        trace!("Received packet with type {:?}", packet_type);
 
-            self.pending_received_packets.as_mut().unwrap().push(packet);
+        let mut packet = packet_type.get_implementation();
+        {
+            let body = &buf.inner()[buf.read_pos()..];
+            let mut slice = Cursor::new(body);
+            packet.read_from(&mut slice)?;
         }
 
-        Ok(())
+        Ok(Some(packet))
     }
+}
 
-    pub fn serialize_packet(&mut self, packet: Box<dyn Packet>) -> ByteBuf {
-        if packet.ty() == PacketType::LoginSuccess {
-            self.stage = PacketStage::Play;
-        }
+impl Encoder<Box<dyn Packet>> for ConnectionIOManager {
+    type Error = CodecError;
 
+    fn encode(&mut self, packet: Box<dyn Packet>, dst: &mut BytesMut) -> Result<(), Self::Error> {
         trace!("Sending packet with type {:?}", packet.ty());
 
         let mut packet_data_buf = ByteBuf::with_capacity(16);
         packet_data_buf.write_var_int(packet.ty().get_id().0 as i32);
         packet.write_to(&mut packet_data_buf);
 
-        let mut buf_without_length = ByteBuf::with_capacity(packet_data_buf.len());
-
-        if self.compression_enabled {
-            let uncompressed_length = packet_data_buf.len();
-
-            if packet_data_buf.len() < self.compression_threshold as usize {
-                buf_without_length.write_var_int(0);
-                buf_without_length
-                    .write_all(packet_data_buf.inner())
-                    .unwrap();
-            } else {
-                buf_without_length.write_var_int(uncompressed_length as i32);
-                self.compress_data(packet_data_buf.inner(), &mut buf_without_length);
-            }
-        } else {
-            buf_without_length
-                .write_all(packet_data_buf.inner())
-                .unwrap(); // Lots of inefficient copying here - find a fix for this
-        }
-
-        let mut buf = ByteBuf::with_capacity(buf_without_length.len() + 4);
-        buf.write_var_int(buf_without_length.len() as i32);
-        buf.write_all(buf_without_length.inner()).unwrap();
+        let frame_start = dst.len();
+        dst.reserve(packet_data_buf.len() + 3);
+        self.format.send(dst, packet_data_buf.inner());
 
         if self.encryption_enabled {
-            self.encrypt_data(buf.bytes_from_start());
+            self.encrypt_data(&mut dst[frame_start..]);
         }
 
-        buf
+        Ok(())
     }
+}
 
-    fn encrypt_data(&mut self, data: &mut [u8]) {
-        let crypter = self.encrypter.as_mut().unwrap();
-        crypter.encrypt(data);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_rejects_frame_over_max_packet_size() {
+        let mut manager = ConnectionIOManager::new(PacketDirection::Serverbound);
+        manager.set_max_packet_size(4);
+
+        // Declares a 100-byte frame, well over the 4-byte limit just
+        // set - should be rejected without waiting for the rest of it
+        // to arrive.
+        let mut src = BytesMut::new();
+        put_var_int(&mut src, 100);
+        src.extend_from_slice(&[0u8; 10]);
+
+        let result = manager.decode_packet(&mut src, PacketStage::Handshake);
+        assert!(matches!(result, Err(CodecError::PacketTooLarge)));
     }
 
-    fn decrypt_data(&mut self, data: &mut [u8]) {
-        let crypter = self.decrypter.as_mut().unwrap();
-        crypter.decrypt(data);
+    #[test]
+    fn decode_rejects_declared_uncompressed_size_over_max_packet_size() {
+        let mut manager = ConnectionIOManager::new(PacketDirection::Serverbound);
+        manager.set_max_packet_size(5);
+        manager.enable_compression(1);
+
+        // A compressed frame body declaring an uncompressed size of
+        // 100 bytes, over the 5-byte limit - rejected from the
+        // declared size alone, without decompressing anything.
+        let mut body = BytesMut::new();
+        put_var_int(&mut body, 100);
+        body.extend_from_slice(&[0u8; 4]);
+
+        let mut src = BytesMut::new();
+        put_var_int(&mut src, body.len() as i32);
+        src.extend_from_slice(&body);
+
+        let result = manager.decode_packet(&mut src, PacketStage::Handshake);
+        assert!(matches!(result, Err(CodecError::PacketTooLarge)));
     }
 
-    fn compress_data(&mut self, data: &[u8], output: &mut ByteBuf) {
-        let mut coder = ZlibEncoder::new(data, Compression::default());
-        output.reserve(coder.total_out() as usize);
+    #[test]
+    fn receive_timed_out_is_false_for_a_freshly_partial_frame() {
+        let mut manager = ConnectionIOManager::new(PacketDirection::Serverbound);
+        assert!(!manager.receive_timed_out());
 
-        unsafe {
-            let amnt = coder.read(output.inner_mut()).unwrap();
-            output.advance_mut(amnt);
-        }
-    }
+        // A lone continuation-bit byte can't possibly be a complete
+        // VarInt length prefix yet.
+        let mut src = BytesMut::from(&[0x80][..]);
+        let result = manager.decode_packet(&mut src, PacketStage::Handshake);
 
-    fn decompress_data(&mut self, uncompressed_size: i32) {
-        let data = &mut self.incoming_compressed;
-        if uncompressed_size == 0 {
-            self.incoming_uncompressed.reserve(data.len());
-            self.incoming_uncompressed.put(data.inner());
-        }
-        let mut coder = ZlibDecoder::new(data);
-        self.incoming_uncompressed
-            .reserve(uncompressed_size as usize);
-        unsafe {
-            let amnt = coder.read(self.incoming_uncompressed.inner_mut()).unwrap();
-            self.incoming_uncompressed.advance_mut(amnt);
-        }
+        assert!(matches!(result, Ok(None)));
+        assert!(!manager.receive_timed_out());
     }
 
-    pub fn take_pending_packets(&mut self) -> Vec<Box<dyn Packet>> {
-        self.pending_received_packets.replace(vec![]).unwrap()
+    #[test]
+    fn decode_detects_legacy_ping_split_across_two_reads() {
+        let mut manager = ConnectionIOManager::new(PacketDirection::Serverbound);
+
+        // The 0xFE/0x01 prefix can arrive in separate reads - seeing
+        // only the first byte must not give up on legacy-ping
+        // detection for the rest of the connection.
+        let mut src = BytesMut::from(&LEGACY_PING_PREFIX[..1]);
+        let result = manager.decode_packet(&mut src, PacketStage::Handshake);
+        assert!(matches!(result, Ok(None)));
+
+        src.extend_from_slice(&LEGACY_PING_PREFIX[1..]);
+        let packet = manager
+            .decode_packet(&mut src, PacketStage::Handshake)
+            .unwrap()
+            .expect("the completed legacy ping prefix should decode to a packet");
+        assert_eq!(packet.ty(), PacketType::LegacyServerListPing);
     }
 }
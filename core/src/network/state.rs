@@ -0,0 +1,30 @@
+//! Marker types for the protocol's state machine - `Handshake`, then
+//! `Status` or `Login`, then `Play` - used to parameterize
+//! [`Connection`](super::connection::Connection).
+
+use super::packet::PacketStage;
+
+pub trait ProtocolState: Send + 'static {
+    const STAGE: PacketStage;
+}
+
+pub struct Handshake;
+pub struct Status;
+pub struct Login;
+pub struct Play;
+
+impl ProtocolState for Handshake {
+    const STAGE: PacketStage = PacketStage::Handshake;
+}
+
+impl ProtocolState for Status {
+    const STAGE: PacketStage = PacketStage::Status;
+}
+
+impl ProtocolState for Login {
+    const STAGE: PacketStage = PacketStage::Login;
+}
+
+impl ProtocolState for Play {
+    const STAGE: PacketStage = PacketStage::Play;
+}